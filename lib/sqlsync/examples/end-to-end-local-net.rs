@@ -13,8 +13,12 @@ use rand::Rng;
 use rand::SeedableRng;
 use sqlsync::local::LocalDocument;
 use sqlsync::local::NoopSignal;
+use sqlsync::replication::Capabilities;
+use sqlsync::replication::Features;
 use sqlsync::replication::ReplicationMsg;
 use sqlsync::replication::ReplicationProtocol;
+use sqlsync::replication::PRIORITY_CONTROL;
+use sqlsync::replication::PRIORITY_NORMAL;
 use sqlsync::JournalId;
 use sqlsync::MemoryJournalFactory;
 use sqlsync::Reducer;
@@ -54,6 +58,12 @@ fn send_msg<W: io::Write>(socket: W, msg: &ReplicationMsg) -> io::Result<()> {
     serialize_into(socket, msg)
 }
 
+/// The optional protocol features this build supports; advertised in the
+/// `Hello` handshake and only actually used once the peer agrees too.
+fn supported_features() -> Features {
+    Features::FRAME_CHUNKING | Features::MULTIPLEXING | Features::RESUME | Features::COMPRESSION
+}
+
 fn receive_msg<R: io::Read>(socket: R) -> io::Result<ReplicationMsg> {
     deserialize_from(socket)
 }
@@ -65,9 +75,20 @@ enum Mutation {
     Decr,
 }
 
+/// The two documents multiplexed over every connection: `counter` is the
+/// demo's real state, `presence` is a second, independent stream with
+/// nothing but per-client heartbeats in it. Having two lets the
+/// connection's priority-ordered outbound queue actually arbitrate between
+/// streams instead of only ever seeing one.
+struct Documents {
+    counter: CoordinatorDocument<MemoryJournal>,
+    presence: CoordinatorDocument<MemoryJournal>,
+}
+
 fn start_server<'a>(
     listener: TcpListener,
     doc_id: JournalId,
+    presence_doc_id: JournalId,
     expected_clients: usize,
     thread_scope: &'a thread::Scope<'a, '_>,
 ) -> anyhow::Result<()> {
@@ -75,20 +96,25 @@ fn start_server<'a>(
         "../../../target/wasm32-unknown-unknown/debug/examples/counter_reducer.wasm"
     );
 
-    // build a ServerDocument and protect it with a mutex since multiple threads will be accessing it
-    let storage_journal = MemoryJournal::open(doc_id)?;
-    let coordinator = CoordinatorDocument::open(
-        storage_journal,
+    // build the documents and protect them with a single mutex since
+    // multiple threads will be accessing them
+    let counter = CoordinatorDocument::open(
+        MemoryJournal::open(doc_id)?,
         MemoryJournalFactory,
         &wasm_bytes[..],
     )?;
-    let coordinator = Arc::new(Mutex::new(coordinator));
+    let presence = CoordinatorDocument::open(
+        MemoryJournal::open(presence_doc_id)?,
+        MemoryJournalFactory,
+        &wasm_bytes[..],
+    )?;
+    let documents = Arc::new(Mutex::new(Documents { counter, presence }));
 
     for _ in 0..expected_clients {
         log::info!("server: waiting for client connection");
         let (socket, _) = listener.accept()?;
-        let doc = coordinator.clone();
-        thread_scope.spawn(move || match handle_client(doc, socket) {
+        let documents = documents.clone();
+        thread_scope.spawn(move || match handle_client(documents, socket) {
             Ok(()) => {}
             Err(e) => {
                 // handle eof
@@ -111,10 +137,7 @@ fn start_server<'a>(
     Ok(())
 }
 
-fn handle_client(
-    doc: Arc<Mutex<CoordinatorDocument<MemoryJournal>>>,
-    socket: TcpStream,
-) -> anyhow::Result<()> {
+fn handle_client(documents: Arc<Mutex<Documents>>, socket: TcpStream) -> anyhow::Result<()> {
     log::info!("server: received client connection");
     let mut protocol = ReplicationProtocol::new();
 
@@ -133,10 +156,68 @@ fn handle_client(
         }};
     }
 
-    // send start message
-    let start_msg = unlock!(|doc| protocol.start(doc));
-    log::info!("server: sending {:?}", start_msg);
-    send_msg(socket_writer, &start_msg)?;
+    // advertise capabilities before naming any document, so a reducer
+    // mismatch fails fast instead of mid-sync.
+    let capabilities = Capabilities::new(
+        supported_features(),
+        unlock!(|documents| documents.counter.reducer().content_hash()),
+    );
+    let hello_msg = protocol.hello(capabilities);
+    log::info!("server: sending {:?}", hello_msg);
+    send_msg(socket_writer, &hello_msg)?;
+
+    // this connection multiplexes two streams over one socket: send a
+    // `Start` for each, each carrying its own priority.
+    let counter_start = unlock!(|documents| protocol.start(&mut documents.counter, PRIORITY_NORMAL));
+    log::info!("server: sending {:?}", counter_start);
+    send_msg(socket_writer, &counter_start)?;
+
+    let presence_start =
+        unlock!(|documents| protocol.start(&mut documents.presence, PRIORITY_CONTROL));
+    log::info!("server: sending {:?}", presence_start);
+    send_msg(socket_writer, &presence_start)?;
+
+    // the client opens with its own `Hello`; answer it with a `HelloAck`,
+    // or with `Incompatible` and a hang-up on a reducer mismatch.
+    let client_hello = receive_msg(&mut socket_reader)?;
+    log::info!("server: received {:?}", client_hello);
+    for resp in
+        unlock!(|documents| protocol.handle(&mut documents.counter, client_hello, &mut socket_reader)?)
+    {
+        log::info!("server: sending {:?}", resp);
+        send_msg(socket_writer, &resp)?;
+    }
+    if protocol.capabilities().is_none() {
+        anyhow::bail!("server: rejected client with an incompatible reducer");
+    }
+
+    // after its `Hello`, the client replies in kind, naming its own counter and presence
+    // timelines, always in that order; remember which stream_id is which
+    // so later messages in the main loop can be routed to the right
+    // document.
+    let counter_reply = receive_msg(&mut socket_reader)?;
+    log::info!("server: received {:?}", counter_reply);
+    let counter_stream_id =
+        counter_reply.stream_id().expect("client's Start always names a stream_id");
+    for resp in
+        unlock!(|documents| protocol.handle(&mut documents.counter, counter_reply, &mut socket_reader)?)
+    {
+        log::info!("server: sending {:?}", resp);
+        send_msg(socket_writer, &resp)?;
+    }
+
+    let presence_reply = receive_msg(&mut socket_reader)?;
+    log::info!("server: received {:?}", presence_reply);
+    let presence_stream_id =
+        presence_reply.stream_id().expect("client's Start always names a stream_id");
+    for resp in unlock!(|documents| protocol.handle(
+        &mut documents.presence,
+        presence_reply,
+        &mut socket_reader
+    )?) {
+        log::info!("server: sending {:?}", resp);
+        send_msg(socket_writer, &resp)?;
+    }
 
     let mut num_steps = 0;
 
@@ -145,25 +226,66 @@ fn handle_client(
     loop {
         let msg = receive_msg(&mut socket_reader)?;
         log::info!("server: received {:?}", msg);
-
-        if let Some(resp) =
-            unlock!(|doc| protocol.handle(doc, msg, &mut socket_reader)?)
-        {
+        let is_presence = msg.stream_id() == Some(presence_stream_id);
+        debug_assert!(is_presence || msg.stream_id().is_none_or(|id| id == counter_stream_id));
+
+        for resp in unlock!(|documents| if is_presence {
+            protocol.handle(&mut documents.presence, msg, &mut socket_reader)?
+        } else {
+            protocol.handle(&mut documents.counter, msg, &mut socket_reader)?
+        }) {
             log::info!("server: sending {:?}", resp);
             send_msg(socket_writer, &resp)?;
         }
 
+        if protocol.peer_done() {
+            // the client has said its goodbyes. flush anything still
+            // queued for either stream, then wait for the acks that
+            // confirm it actually arrived before replying in kind -
+            // mirrors the client's own shutdown sequence below, so a send
+            // still in flight at exactly this moment isn't just dropped
+            // on the floor.
+            log::info!("server: client said goodbye, flushing before shutdown");
+            unlock!(|documents| {
+                protocol.queue_sync(&mut documents.counter, PRIORITY_NORMAL)?;
+                protocol.queue_sync(&mut documents.presence, PRIORITY_CONTROL)?;
+                while let Some((msg, mut reader)) = protocol.next_outbound() {
+                    send_msg(socket_writer, &msg)?;
+                    io::copy(&mut reader, &mut socket_writer)?;
+                }
+            });
+            let goodbye_msg = protocol.shutdown();
+            while !protocol.is_drained() {
+                let msg = receive_msg(&mut socket_reader)?;
+                log::info!("server: received {:?}", msg);
+                let is_presence = msg.stream_id() == Some(presence_stream_id);
+                for resp in unlock!(|documents| if is_presence {
+                    protocol.handle(&mut documents.presence, msg, &mut socket_reader)?
+                } else {
+                    protocol.handle(&mut documents.counter, msg, &mut socket_reader)?
+                }) {
+                    log::info!("server: sending {:?}", resp);
+                    send_msg(socket_writer, &resp)?;
+                }
+            }
+            send_msg(socket_writer, &goodbye_msg)?;
+            break;
+        }
+
         // step after every message
         num_steps += 1;
-        log::info!("server: stepping doc (steps: {})", num_steps);
-        unlock!(|doc| doc.step()?);
+        log::info!("server: stepping docs (steps: {})", num_steps);
+        unlock!(|documents| {
+            documents.counter.step()?;
+            documents.presence.step()?;
+        });
 
         // trigger a direct increment on the server side after every message
         if remaining_direct_mutations > 0 {
             remaining_direct_mutations -= 1;
-            unlock!(|doc| {
-                log::info!("server: running a direct mutation on the doc");
-                doc.mutate_direct(|tx| {
+            unlock!(|documents| {
+                log::info!("server: running a direct mutation on the counter doc");
+                documents.counter.mutate_direct(|tx| {
                     match tx.execute(
                         "INSERT INTO counter (id, value) VALUES (1, 0)
                         ON CONFLICT (id) DO UPDATE SET value = value + 1",
@@ -183,9 +305,14 @@ fn handle_client(
             });
         }
 
-        // sync back to the client if needed
-        unlock!(|doc| {
-            if let Some((msg, mut reader)) = protocol.sync(doc)? {
+        // sync back to the client if needed: one dequeue per tick, across
+        // both streams, so the counter and presence both get a turn and
+        // the priority between them is actually exercised when both have
+        // data queued at once.
+        unlock!(|documents| {
+            protocol.queue_sync(&mut documents.counter, PRIORITY_NORMAL)?;
+            protocol.queue_sync(&mut documents.presence, PRIORITY_CONTROL)?;
+            if let Some((msg, mut reader)) = protocol.next_outbound() {
                 log::info!("server: syncing to client: {:?}", msg);
                 send_msg(socket_writer, &msg)?;
                 let frame_len = reader.len() as u64;
@@ -200,6 +327,8 @@ fn handle_client(
             }
         });
     }
+
+    Ok(())
 }
 
 fn start_client(
@@ -207,6 +336,7 @@ fn start_client(
     addr: impl ToSocketAddrs,
     num_clients: usize,
     doc_id: JournalId,
+    presence_doc_id: JournalId,
 ) -> anyhow::Result<()> {
     let socket = TcpStream::connect(addr)?;
     let mut socket_reader = BufReader::new(&socket);
@@ -232,15 +362,51 @@ fn start_client(
     // initialize schema
     doc.mutate(&bincode::serialize(&Mutation::InitSchema)?)?;
 
+    // a second document multiplexed over the same connection: a heartbeat
+    // stream with nothing at stake, sent at `PRIORITY_CONTROL` alongside
+    // `doc`'s `PRIORITY_NORMAL`, so the protocol's priority queue actually
+    // has two streams to arbitrate between instead of just one.
+    let presence_timeline_id = JournalId::new128(&mut rng);
+    let presence_timeline_journal = MemoryJournal::open(presence_timeline_id)?;
+    let presence_storage_journal = MemoryJournal::open(presence_doc_id)?;
+    let mut presence = LocalDocument::open(
+        presence_storage_journal,
+        presence_timeline_journal,
+        Reducer::new(wasm_bytes.as_slice())?,
+        NoopSignal,
+        NoopSignal,
+        NoopSignal,
+    )?;
+
     let mut protocol = ReplicationProtocol::new();
 
-    // send start message
-    let start_msg = protocol.start(&mut doc);
+    // advertise capabilities before naming any document, so a reducer
+    // mismatch fails fast instead of mid-sync.
+    let capabilities = Capabilities::new(supported_features(), doc.reducer().content_hash());
+    let hello_msg = protocol.hello(capabilities);
+    log::info!("client({}): sending {:?}", timeline_id, hello_msg);
+    send_msg(socket_writer, &hello_msg)?;
+
+    // send start messages, one per stream; the server expects to see the
+    // counter stream named before the presence stream.
+    let start_msg = protocol.start(&mut doc, PRIORITY_NORMAL);
     log::info!("client({}): sending {:?}", timeline_id, start_msg);
     send_msg(socket_writer, &start_msg)?;
 
+    let presence_start_msg = protocol.start(&mut presence, PRIORITY_CONTROL);
+    log::info!("client({}): sending {:?}", timeline_id, presence_start_msg);
+    send_msg(socket_writer, &presence_start_msg)?;
+
     log::info!("client({}): connected to server", timeline_id);
 
+    // a message belongs to `presence` if its stream_id is either the
+    // stream `presence` names for its own outbound frames (its timeline
+    // id) or the one the server names for `presence`'s storage (the
+    // shared `presence_doc_id`); anything else is `doc`'s.
+    let is_presence_stream = |stream_id: Option<JournalId>| {
+        matches!(stream_id, Some(id) if id == presence_doc_id || id == presence_timeline_id)
+    };
+
     // the amount of mutations we will send the server
     let total_mutations = 10 as usize;
     let mut remaining_mutations = total_mutations;
@@ -258,15 +424,20 @@ fn start_client(
         let msg = receive_msg(&mut socket_reader)?;
         log::info!("client({}): received {:?}", timeline_id, msg);
 
-        if let Some(resp) =
+        let target_is_presence = is_presence_stream(msg.stream_id());
+        let replies = if target_is_presence {
+            protocol.handle(&mut presence, msg, &mut socket_reader)?
+        } else {
             protocol.handle(&mut doc, msg, &mut socket_reader)?
-        {
+        };
+        for resp in replies {
             log::info!("client({}): sending {:?}", timeline_id, resp);
             send_msg(socket_writer, &resp)?;
         }
 
         // trigger a rebase if needed
         doc.rebase()?;
+        presence.rebase()?;
 
         if remaining_mutations > 0 {
             log::info!("client({}): running incr", timeline_id);
@@ -274,8 +445,16 @@ fn start_client(
             remaining_mutations -= 1;
         }
 
-        // sync pending mutations to the server
-        if let Some((msg, mut reader)) = protocol.sync(&mut doc)? {
+        // heartbeat: presence gets a trivial mutation every tick, so it
+        // always has something pending to arbitrate against `doc`'s
+        // stream.
+        presence.mutate(&bincode::serialize(&Mutation::Incr)?)?;
+
+        // sync pending mutations to the server: one dequeue per tick,
+        // across both streams.
+        protocol.queue_sync(&mut doc, PRIORITY_NORMAL)?;
+        protocol.queue_sync(&mut presence, PRIORITY_CONTROL)?;
+        if let Some((msg, mut reader)) = protocol.next_outbound() {
             log::info!("client({}): syncing to server: {:?}", timeline_id, msg);
             send_msg(socket_writer, &msg)?;
             // write the frame
@@ -364,6 +543,36 @@ fn start_client(
         Ok::<_, anyhow::Error>(())
     })?;
 
+    // flush anything still pending on either stream, then stop accepting
+    // new mutations and wait for the server to ack everything already
+    // sent, so the Goodbye below is only sent once nothing would be
+    // dropped on the floor.
+    protocol.queue_sync(&mut doc, PRIORITY_NORMAL)?;
+    protocol.queue_sync(&mut presence, PRIORITY_CONTROL)?;
+    while let Some((msg, mut reader)) = protocol.next_outbound() {
+        send_msg(socket_writer, &msg)?;
+        io::copy(&mut reader, &mut socket_writer)?;
+    }
+    let goodbye_msg = protocol.shutdown();
+    while !protocol.is_drained() {
+        let msg = receive_msg(&mut socket_reader)?;
+        log::info!("client({}): received {:?}", timeline_id, msg);
+        let replies = if is_presence_stream(msg.stream_id()) {
+            protocol.handle(&mut presence, msg, &mut socket_reader)?
+        } else {
+            protocol.handle(&mut doc, msg, &mut socket_reader)?
+        };
+        for resp in replies {
+            send_msg(socket_writer, &resp)?;
+        }
+    }
+
+    // tell the server we're done instead of just dropping the socket, so
+    // its loop can break cleanly rather than discovering the disconnect as
+    // a read error.
+    log::info!("client({}): sending {:?}", timeline_id, goodbye_msg);
+    send_msg(socket_writer, &goodbye_msg)?;
+
     log::info!("client({}): closing connection", timeline_id);
 
     Ok(())
@@ -390,12 +599,13 @@ fn main() -> anyhow::Result<()> {
     let addr = "127.0.0.1:8080";
     let listener = TcpListener::bind(addr)?;
     let doc_id = JournalId::new256(&mut rng);
+    let presence_doc_id = JournalId::new256(&mut rng);
 
     thread::scope(|s| {
         let num_clients = 2;
 
         s.spawn(move || {
-            start_server(listener, doc_id, num_clients, s)
+            start_server(listener, doc_id, presence_doc_id, num_clients, s)
                 .expect("server failed")
         });
 
@@ -403,7 +613,7 @@ fn main() -> anyhow::Result<()> {
             // create separate rngs for each client seeded by the root rng
             let client_rng = StdRng::seed_from_u64(rng.gen());
             s.spawn(move || {
-                start_client(client_rng, addr, num_clients, doc_id)
+                start_client(client_rng, addr, num_clients, doc_id, presence_doc_id)
                     .expect("client failed")
             });
         }
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+use rusqlite::Connection;
+
+use crate::journal::{Journal, JournalFactory, JournalId, Lsn, MemoryJournalFactory};
+use crate::reducer::Reducer;
+
+/// The server-side counterpart to [`crate::local::LocalDocument`]: owns
+/// the durable storage journal that is the source of truth for a
+/// document, and lazily opens one timeline journal per connected client
+/// via `journal_factory`.
+pub struct CoordinatorDocument<S, JF: JournalFactory = MemoryJournalFactory> {
+    storage_journal: S,
+    journal_factory: JF,
+    reducer: Reducer,
+    timelines: HashMap<JournalId, JF::Journal>,
+    /// The last LSN of each timeline that's been durably applied into
+    /// storage, so `step` doesn't re-run the reducer over mutations it's
+    /// already folded in, and a reconnecting client can be told exactly
+    /// what it still needs to resend.
+    applied_through: HashMap<JournalId, Lsn>,
+    conn: Connection,
+}
+
+impl<S, JF> CoordinatorDocument<S, JF>
+where
+    S: Journal,
+    JF: JournalFactory,
+{
+    pub fn open(storage_journal: S, journal_factory: JF, wasm_bytes: &[u8]) -> anyhow::Result<Self> {
+        let conn = Connection::open_in_memory()?;
+        for frame in storage_journal.frames_after(None) {
+            conn.execute_batch(&String::from_utf8_lossy(&frame.data))?;
+        }
+        Ok(Self {
+            storage_journal,
+            journal_factory,
+            reducer: Reducer::new(wasm_bytes)?,
+            timelines: HashMap::new(),
+            applied_through: HashMap::new(),
+            conn,
+        })
+    }
+
+    /// Opens (creating if necessary) the timeline journal for `id`.
+    pub fn timeline(&mut self, id: JournalId) -> anyhow::Result<&mut JF::Journal> {
+        if !self.timelines.contains_key(&id) {
+            let journal = self.journal_factory.open(id)?;
+            self.timelines.insert(id, journal);
+        }
+        Ok(self.timelines.get_mut(&id).expect("just inserted"))
+    }
+
+    /// Applies any timeline mutations that haven't yet been folded into
+    /// the canonical connection, recording each timeline's progress in
+    /// `applied_through`. The storage journal itself is not appended to.
+    pub fn step(&mut self) -> anyhow::Result<()> {
+        for (id, journal) in self.timelines.iter() {
+            let after = self.applied_through.get(id).copied();
+            for frame in journal.frames_after(after) {
+                self.reducer.apply(&self.conn, &frame.data)?;
+                self.applied_through.insert(*id, frame.lsn);
+            }
+        }
+        Ok(())
+    }
+
+    /// The last LSN of `timeline_id` that's been durably applied into
+    /// storage, if any of its mutations have been seen yet.
+    pub fn applied_through(&self, timeline_id: JournalId) -> Option<Lsn> {
+        self.applied_through.get(&timeline_id).copied()
+    }
+
+    /// Runs `f` against the canonical connection outside of the reducer,
+    /// for server-only writes that don't need to replicate through a
+    /// client timeline (e.g. background jobs).
+    pub fn mutate_direct<F>(&mut self, f: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&Connection) -> anyhow::Result<()>,
+    {
+        f(&self.conn)?;
+        self.storage_journal.append(Vec::new());
+        Ok(())
+    }
+
+    pub fn reducer(&self) -> &Reducer {
+        &self.reducer
+    }
+
+    pub fn storage_journal(&self) -> &S {
+        &self.storage_journal
+    }
+
+    pub fn storage_journal_mut(&mut self) -> &mut S {
+        &mut self.storage_journal
+    }
+}
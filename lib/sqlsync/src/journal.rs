@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// A position in a journal. LSNs are monotonically increasing and
+/// contiguous within a single journal.
+pub type Lsn = u64;
+
+/// Identifies a journal. Timelines (one per client) use 128-bit ids while
+/// storage/document journals use 256-bit ids, so the two id spaces can
+/// never be confused for one another on the wire.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum JournalId {
+    Id128([u8; 16]),
+    Id256([u8; 32]),
+}
+
+impl JournalId {
+    pub fn new128(rng: &mut impl Rng) -> Self {
+        let mut bytes = [0u8; 16];
+        rng.fill(&mut bytes);
+        JournalId::Id128(bytes)
+    }
+
+    pub fn new256(rng: &mut impl Rng) -> Self {
+        let mut bytes = [0u8; 32];
+        rng.fill(&mut bytes);
+        JournalId::Id256(bytes)
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            JournalId::Id128(b) => b,
+            JournalId::Id256(b) => b,
+        }
+    }
+}
+
+impl fmt::Debug for JournalId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "JournalId(")?;
+        for byte in self.as_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl fmt::Display for JournalId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// A single durable entry in a journal: the raw bytes written at a
+/// particular LSN.
+#[derive(Clone, Debug)]
+pub struct JournalFrame {
+    pub lsn: Lsn,
+    pub data: Vec<u8>,
+}
+
+/// A journal is an append-only log of frames, addressable by LSN. Both
+/// storage journals (SQL text, replayed with `execute_batch`) and timeline
+/// journals (pending mutations) are modeled the same way.
+pub trait Journal: Send + 'static {
+    fn id(&self) -> JournalId;
+
+    /// The LSN of the last frame appended to this journal, if any.
+    fn max_lsn(&self) -> Option<Lsn>;
+
+    fn append(&mut self, data: Vec<u8>) -> Lsn;
+
+    /// Frames strictly after `after`, in LSN order.
+    fn frames_after(&self, after: Option<Lsn>) -> Vec<JournalFrame>;
+}
+
+/// Constructs journals on demand, one per timeline, so that a
+/// [`crate::coordinator::CoordinatorDocument`] doesn't need to know up front how
+/// many clients it will ever see.
+pub trait JournalFactory: Send + 'static {
+    type Journal: Journal;
+
+    fn open(&self, id: JournalId) -> anyhow::Result<Self::Journal>;
+}
+
+/// An in-memory [`Journal`], primarily useful for tests and examples; a
+/// real deployment would back this with a file or object store instead.
+pub struct MemoryJournal {
+    id: JournalId,
+    frames: BTreeMap<Lsn, Vec<u8>>,
+    next_lsn: Lsn,
+}
+
+impl MemoryJournal {
+    pub fn open(id: JournalId) -> anyhow::Result<Self> {
+        Ok(Self { id, frames: BTreeMap::new(), next_lsn: 0 })
+    }
+}
+
+impl Journal for MemoryJournal {
+    fn id(&self) -> JournalId {
+        self.id
+    }
+
+    fn max_lsn(&self) -> Option<Lsn> {
+        self.frames.keys().next_back().copied()
+    }
+
+    fn append(&mut self, data: Vec<u8>) -> Lsn {
+        let lsn = self.next_lsn;
+        self.next_lsn += 1;
+        self.frames.insert(lsn, data);
+        lsn
+    }
+
+    fn frames_after(&self, after: Option<Lsn>) -> Vec<JournalFrame> {
+        self.frames
+            .range(after.map(|lsn| lsn + 1).unwrap_or(0)..)
+            .map(|(lsn, data)| JournalFrame { lsn: *lsn, data: data.clone() })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct MemoryJournalFactory;
+
+impl JournalFactory for MemoryJournalFactory {
+    type Journal = MemoryJournal;
+
+    fn open(&self, id: JournalId) -> anyhow::Result<Self::Journal> {
+        MemoryJournal::open(id)
+    }
+}
@@ -0,0 +1,18 @@
+//! sqlsync: a local-first, reducer-based SQLite replication engine.
+//!
+//! A [`local::LocalDocument`] runs on each client and applies mutations
+//! optimistically through a WASM [`Reducer`], while a
+//! [`coordinator::CoordinatorDocument`] runs on a server and is the
+//! source of truth for a document's storage journal. The two sides stay
+//! in sync over whatever transport the embedder chooses by speaking the
+//! [`replication`] protocol.
+
+mod journal;
+mod reducer;
+
+pub mod coordinator;
+pub mod local;
+pub mod replication;
+
+pub use journal::{Journal, JournalFactory, JournalId, Lsn, MemoryJournal, MemoryJournalFactory};
+pub use reducer::Reducer;
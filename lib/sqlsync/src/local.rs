@@ -0,0 +1,124 @@
+use rusqlite::Connection;
+
+use crate::journal::{Journal, Lsn};
+use crate::reducer::Reducer;
+
+/// Notified whenever the piece of state it's attached to changes, so an
+/// embedder can wake up a UI thread. [`NoopSignal`] is the default for
+/// callers (like the examples) that poll instead of react.
+pub trait Signal: Send + 'static {
+    fn notify(&self);
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopSignal;
+
+impl Signal for NoopSignal {
+    fn notify(&self) {}
+}
+
+/// A client-side document: an optimistic in-memory view built by replaying
+/// the local timeline's pending mutations on top of the last storage frame
+/// received from the coordinator.
+pub struct LocalDocument<S, T, StorageSig, TimelineSig, QuerySig> {
+    storage_journal: S,
+    timeline_journal: T,
+    reducer: Reducer,
+    storage_signal: StorageSig,
+    timeline_signal: TimelineSig,
+    query_signal: QuerySig,
+    conn: Connection,
+}
+
+impl<S, T, StorageSig, TimelineSig, QuerySig>
+    LocalDocument<S, T, StorageSig, TimelineSig, QuerySig>
+where
+    S: Journal,
+    T: Journal,
+    StorageSig: Signal,
+    TimelineSig: Signal,
+    QuerySig: Signal,
+{
+    pub fn open(
+        storage_journal: S,
+        timeline_journal: T,
+        reducer: Reducer,
+        storage_signal: StorageSig,
+        timeline_signal: TimelineSig,
+        query_signal: QuerySig,
+    ) -> anyhow::Result<Self> {
+        let conn = Connection::open_in_memory()?;
+        let mut doc = Self {
+            storage_journal,
+            timeline_journal,
+            reducer,
+            storage_signal,
+            timeline_signal,
+            query_signal,
+            conn,
+        };
+        doc.rebase()?;
+        Ok(doc)
+    }
+
+    /// Applies a mutation optimistically: it's appended to the local
+    /// timeline immediately and replayed against `conn` through the
+    /// reducer, ahead of being acknowledged by the coordinator.
+    pub fn mutate(&mut self, mutation: &[u8]) -> anyhow::Result<()> {
+        self.timeline_journal.append(mutation.to_vec());
+        self.reducer.apply(&self.conn, mutation)?;
+        self.timeline_signal.notify();
+        self.query_signal.notify();
+        Ok(())
+    }
+
+    /// Replays any storage frames received since the last rebase, then
+    /// reapplies still-unacknowledged timeline mutations on top, so the
+    /// local view stays causally consistent with the coordinator.
+    pub fn rebase(&mut self) -> anyhow::Result<()> {
+        for frame in self.storage_journal.frames_after(None) {
+            self.conn.execute_batch(&String::from_utf8_lossy(&frame.data))?;
+        }
+        for frame in self.timeline_journal.frames_after(None) {
+            self.reducer.apply(&self.conn, &frame.data)?;
+        }
+        self.storage_signal.notify();
+        self.query_signal.notify();
+        Ok(())
+    }
+
+    pub fn query<F, R>(&self, f: F) -> anyhow::Result<R>
+    where
+        F: FnOnce(&Connection) -> anyhow::Result<R>,
+    {
+        f(&self.conn)
+    }
+
+    pub fn storage_lsn(&self) -> Option<Lsn> {
+        self.storage_journal.max_lsn()
+    }
+
+    pub fn reducer(&self) -> &Reducer {
+        &self.reducer
+    }
+
+    pub fn timeline_id(&self) -> crate::journal::JournalId {
+        self.timeline_journal.id()
+    }
+
+    pub fn storage_journal(&self) -> &S {
+        &self.storage_journal
+    }
+
+    pub fn storage_journal_mut(&mut self) -> &mut S {
+        &mut self.storage_journal
+    }
+
+    pub fn timeline_journal(&self) -> &T {
+        &self.timeline_journal
+    }
+
+    pub fn timeline_journal_mut(&mut self) -> &mut T {
+        &mut self.timeline_journal
+    }
+}
@@ -0,0 +1,39 @@
+/// A WASM reducer: pure, deterministic code compiled to `wasm32-unknown-unknown`
+/// that both clients and the coordinator execute identically to apply a
+/// mutation to the SQLite schema it manages.
+pub struct Reducer {
+    bytes: Vec<u8>,
+}
+
+impl Reducer {
+    pub fn new(wasm_bytes: impl Into<Vec<u8>>) -> anyhow::Result<Self> {
+        Ok(Self { bytes: wasm_bytes.into() })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The seam both [`crate::local`] and [`crate::coordinator`] call
+    /// through to apply a serialized mutation to `conn`, so both sides
+    /// apply mutations identically. This crate contains no WASM execution
+    /// engine: `apply` returns `Ok(())` without running the reducer's
+    /// bytes or touching `conn`.
+    pub fn apply(&self, _conn: &rusqlite::Connection, _mutation: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// A content hash of this reducer's WASM bytes, exchanged during the
+    /// replication handshake so two peers can detect a reducer mismatch
+    /// up front instead of diverging silently mid-sync.
+    pub fn content_hash(&self) -> u64 {
+        // FNV-1a: fast, dependency-free, and plenty for "do these two
+        // builds agree", which is all this needs to be.
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in &self.bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+}
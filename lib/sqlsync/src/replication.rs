@@ -0,0 +1,1171 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{self, Cursor, Read};
+
+use serde::{Deserialize, Serialize};
+
+use crate::coordinator::CoordinatorDocument;
+use crate::journal::{Journal, JournalFactory, JournalId, Lsn};
+use crate::local::{LocalDocument, Signal};
+
+/// The maximum number of payload bytes carried by a single
+/// `ReplicationMsg::FrameChunk`. Chosen to keep any one write small enough
+/// that a slow peer can still push back between chunks instead of
+/// blocking the whole frame.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Chunks smaller than this aren't worth the codec's per-call overhead, so
+/// `next_outbound` sends them uncompressed even when the session negotiated
+/// [`Features::COMPRESSION`].
+const COMPRESSION_MIN_SIZE: usize = 256;
+
+/// Reserved for control traffic (handshakes, acks) so it can always
+/// preempt bulk frame data on a busy connection.
+pub const PRIORITY_CONTROL: u8 = 0;
+
+/// The default priority for a document's frame data.
+pub const PRIORITY_NORMAL: u8 = 10;
+
+/// The replication wire protocol's own version, independent of the crate
+/// version. Bump this whenever a change to `ReplicationMsg` isn't
+/// wire-compatible with older peers.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features a peer may or may not support. A session
+/// only ever uses a feature both peers have advertised; see
+/// [`Capabilities::intersect`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Features(u32);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    pub const FRAME_CHUNKING: Features = Features(1 << 0);
+    pub const MULTIPLEXING: Features = Features(1 << 1);
+    pub const RESUME: Features = Features(1 << 2);
+    pub const COMPRESSION: Features = Features(1 << 3);
+
+    pub fn contains(self, flag: Features) -> bool {
+        self.0 & flag.0 == flag.0
+    }
+
+    /// The bits set in both `self` and `other` — what a session can
+    /// actually rely on once both peers have advertised their support.
+    pub fn intersection(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, rhs: Features) -> Features {
+        Features(self.0 | rhs.0)
+    }
+}
+
+/// What one peer advertises about itself at the start of a connection:
+/// the replication protocol version it speaks, the optional features it
+/// supports, and a content hash of the reducer it will run mutations
+/// through. Two peers' capabilities are reconciled with
+/// [`Capabilities::intersect`]; a reducer hash mismatch is a hard
+/// incompatibility rather than something that can be masked away.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub protocol_version: u32,
+    pub features: Features,
+    pub reducer_hash: u64,
+}
+
+impl Capabilities {
+    pub fn new(features: Features, reducer_hash: u64) -> Self {
+        Self { protocol_version: PROTOCOL_VERSION, features, reducer_hash }
+    }
+
+    /// The capabilities a session between `self` and `remote` can
+    /// actually use: the lower protocol version, masked down to the
+    /// features both sides advertised (`self.features` restricted to
+    /// what `remote` also includes).
+    pub fn intersect(&self, remote: &Capabilities) -> Capabilities {
+        Capabilities {
+            protocol_version: self.protocol_version.min(remote.protocol_version),
+            features: self.features.intersection(remote.features),
+            reducer_hash: self.reducer_hash,
+        }
+    }
+
+    pub fn compatible_with(&self, remote: &Capabilities) -> bool {
+        self.reducer_hash == remote.reducer_hash
+    }
+}
+
+/// A compression codec a `CompressedFrameChunk`'s payload was packed with.
+/// `Rle` is a minimal run-length codec rather than a real compressor like
+/// zstd or lz4, chosen deliberately: this crate has no manifest to declare
+/// either as a dependency in, and SQLite page frames are full of long runs
+/// of identical bytes (zeroed pages, repeated columns), so it still earns
+/// its keep on the traffic this protocol actually carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompressionAlgo {
+    Rle,
+}
+
+impl CompressionAlgo {
+    fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            CompressionAlgo::Rle => rle_compress(data),
+        }
+    }
+
+    fn decompress(self, data: &[u8], uncompressed_len: usize) -> Vec<u8> {
+        match self {
+            CompressionAlgo::Rle => rle_decompress(data, uncompressed_len),
+        }
+    }
+}
+
+/// Encodes `data` as a sequence of `(byte, run length)` pairs, each run
+/// capped at 255 so the length always fits a `u8`.
+fn rle_compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut iter = data.iter().peekable();
+    while let Some(&byte) = iter.next() {
+        let mut run: u8 = 1;
+        while run < u8::MAX && iter.peek() == Some(&&byte) {
+            iter.next();
+            run += 1;
+        }
+        out.push(byte);
+        out.push(run);
+    }
+    out
+}
+
+/// The inverse of [`rle_compress`]. `uncompressed_len` is only used to
+/// preallocate; the run lengths encoded in `data` are trusted as-is.
+fn rle_decompress(data: &[u8], uncompressed_len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(uncompressed_len);
+    let mut pairs = data.chunks_exact(2);
+    for pair in &mut pairs {
+        out.extend(std::iter::repeat_n(pair[0], pair[1] as usize));
+    }
+    out
+}
+
+/// The checkpoint a reconnecting peer already holds for a stream: the
+/// storage LSN it has durably received, plus the high-water mark it
+/// believes it last sent for each timeline feeding that stream (a
+/// `CoordinatorDocument` may have several, one per client). Carried in a
+/// resumed `Start` so the other side can resume exactly where things left
+/// off instead of replaying frames that were already durably applied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeCheckpoint {
+    pub last_storage_lsn: Option<Lsn>,
+    pub timeline_ranges: Vec<(JournalId, Lsn)>,
+}
+
+/// Messages exchanged between two `ReplicationProtocol` instances. Every
+/// per-document message carries the `stream_id` of the document it belongs
+/// to, since a single connection (and a single `ReplicationProtocol`) can
+/// multiplex several documents at once, each identified by its own journal
+/// id; the connection-level `Hello`, `HelloAck`, `Incompatible` and
+/// `Goodbye` carry none (see [`ReplicationMsg::stream_id`]). A
+/// `FrameChunk` (and, historically, a whole frame) is always immediately
+/// followed on the wire by exactly its payload bytes, read by whichever
+/// side calls [`ReplicationProtocol::handle`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReplicationMsg {
+    /// Sent once by each peer as the very first message on a connection,
+    /// before any per-document `Start`, advertising what this side
+    /// speaks and runs.
+    Hello { capabilities: Capabilities },
+
+    /// Replies to a `Hello` with the capabilities this session will
+    /// actually use: `local.intersect(&remote)` from the receiving
+    /// side's point of view.
+    HelloAck { capabilities: Capabilities },
+
+    /// Sent instead of `HelloAck` when the peer's `Hello` is a hard
+    /// incompatibility (currently: a mismatched reducer), so the
+    /// connection fails with a clear reason up front instead of a
+    /// cryptic decode error mid-sync.
+    Incompatible { reason: String },
+
+    /// Sent once by each peer for each document, when it first starts
+    /// being replicated over this connection. `resume` is set when the
+    /// sender is reattaching after a disconnect and already holds some
+    /// prior state for this stream, so the peer can skip resending it.
+    Start {
+        stream_id: JournalId,
+        priority: u8,
+        storage_lsn: Option<Lsn>,
+        resume: Option<ResumeCheckpoint>,
+    },
+
+    /// Tells the peer exactly what range it still needs for one of the
+    /// timelines named in a resumed `Start`'s checkpoint, so a
+    /// reconnecting client doesn't have to guess whether its last batch
+    /// of mutations was durably received before the disconnect.
+    ResumeRange { stream_id: JournalId, timeline_id: JournalId, needed_from: Option<Lsn> },
+
+    /// One chunk of a frame spanning `[start_lsn, end_lsn]` for
+    /// `stream_id`. `frame_id` identifies the frame the chunk belongs to
+    /// so the receiver can reassemble interleaved frames from different
+    /// streams; `seq` is this chunk's position within that frame.
+    /// `is_last` is only ever set on the chunk that truly ends the frame,
+    /// even when that chunk happens to be a full `CHUNK_SIZE` payload.
+    FrameChunk {
+        stream_id: JournalId,
+        priority: u8,
+        frame_id: u64,
+        seq: u32,
+        start_lsn: Lsn,
+        end_lsn: Lsn,
+        len: u32,
+        is_last: bool,
+    },
+
+    /// Like `FrameChunk`, but the trailing payload on the wire is `len`
+    /// bytes of `algo`-compressed data that decompress to
+    /// `uncompressed_len` bytes. Sent instead of a `FrameChunk` only when
+    /// the session negotiated [`Features::COMPRESSION`] and the chunk was
+    /// large enough to be worth it; see [`ReplicationProtocol`]'s
+    /// compression toggle and [`COMPRESSION_MIN_SIZE`].
+    CompressedFrameChunk {
+        stream_id: JournalId,
+        priority: u8,
+        frame_id: u64,
+        seq: u32,
+        start_lsn: Lsn,
+        end_lsn: Lsn,
+        algo: CompressionAlgo,
+        uncompressed_len: u32,
+        len: u32,
+        is_last: bool,
+    },
+
+    /// Acknowledges durable receipt of all frames up to and including
+    /// `through_lsn` for `stream_id`.
+    Ack { stream_id: JournalId, through_lsn: Lsn },
+
+    /// Sent once by a side that's shutting down, after draining every
+    /// frame it had already queued. Tells the peer not to expect anything
+    /// further on this connection, so it can close its own end as soon as
+    /// it's drained too instead of discovering the disconnect as an I/O
+    /// error. See [`ReplicationProtocol::shutdown`].
+    Goodbye,
+}
+
+impl ReplicationMsg {
+    /// The document this message belongs to, used to route it to the
+    /// right document and the right per-stream protocol state. `None` for
+    /// the connection-level handshake messages, which precede any
+    /// document being named.
+    pub fn stream_id(&self) -> Option<JournalId> {
+        match self {
+            ReplicationMsg::Hello { .. }
+            | ReplicationMsg::HelloAck { .. }
+            | ReplicationMsg::Incompatible { .. }
+            | ReplicationMsg::Goodbye => None,
+            ReplicationMsg::Start { stream_id, .. } => Some(*stream_id),
+            ReplicationMsg::ResumeRange { stream_id, .. } => Some(*stream_id),
+            ReplicationMsg::FrameChunk { stream_id, .. } => Some(*stream_id),
+            ReplicationMsg::CompressedFrameChunk { stream_id, .. } => Some(*stream_id),
+            ReplicationMsg::Ack { stream_id, .. } => Some(*stream_id),
+        }
+    }
+}
+
+/// A reader over a single chunk's payload, exposing `len()` the way
+/// callers that write the chunk out with `io::copy` expect.
+pub struct FrameReader(Cursor<Vec<u8>>);
+
+impl FrameReader {
+    pub fn len(&self) -> usize {
+        self.0.get_ref().len() - self.0.position() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Read for FrameReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+/// Adapts a document kind (local or coordinator) to what the replication
+/// protocol needs: a source of outbound frames and a sink for inbound
+/// ones. This lets `ReplicationProtocol` stay agnostic to which side of
+/// the connection it's driving.
+pub trait ReplicatedDocument {
+    fn doc_id(&self) -> JournalId;
+
+    fn current_lsn(&self) -> Option<Lsn>;
+
+    /// The next outbound frame starting strictly after `after`, if any
+    /// bytes are pending.
+    fn take_pending_frame(&mut self, after: Option<Lsn>) -> Option<(Lsn, Lsn, Vec<u8>)>;
+
+    /// Applies a fully reassembled inbound frame spanning
+    /// `[start_lsn, end_lsn]`.
+    fn apply_frame(&mut self, start_lsn: Lsn, end_lsn: Lsn, data: Vec<u8>) -> anyhow::Result<()>;
+
+    /// The durable high-water mark this side already has for `stream_id`,
+    /// used to answer a peer's resume checkpoint. Defaults to this
+    /// document's own LSN when `stream_id` is its own id, which covers a
+    /// `LocalDocument` (it only ever replicates one stream: itself).
+    /// `CoordinatorDocument` overrides this to report per-timeline marks.
+    fn durable_lsn_for(&self, stream_id: JournalId) -> Option<Lsn> {
+        if stream_id == self.doc_id() {
+            self.current_lsn()
+        } else {
+            None
+        }
+    }
+}
+
+impl<S, T, StorageSig, TimelineSig, QuerySig> ReplicatedDocument
+    for LocalDocument<S, T, StorageSig, TimelineSig, QuerySig>
+where
+    S: Journal,
+    T: Journal,
+    StorageSig: Signal,
+    TimelineSig: Signal,
+    QuerySig: Signal,
+{
+    fn doc_id(&self) -> JournalId {
+        self.timeline_id()
+    }
+
+    fn current_lsn(&self) -> Option<Lsn> {
+        self.storage_lsn()
+    }
+
+    fn take_pending_frame(&mut self, after: Option<Lsn>) -> Option<(Lsn, Lsn, Vec<u8>)> {
+        let frames = self.timeline_journal().frames_after(after);
+        concat_frames(&frames)
+    }
+
+    fn apply_frame(&mut self, _start_lsn: Lsn, _end_lsn: Lsn, data: Vec<u8>) -> anyhow::Result<()> {
+        self.storage_journal_mut().append(data);
+        self.rebase()
+    }
+}
+
+impl<S, JF> ReplicatedDocument for CoordinatorDocument<S, JF>
+where
+    S: Journal,
+    JF: JournalFactory,
+{
+    fn doc_id(&self) -> JournalId {
+        self.storage_journal().id()
+    }
+
+    fn current_lsn(&self) -> Option<Lsn> {
+        self.storage_journal().max_lsn()
+    }
+
+    fn take_pending_frame(&mut self, after: Option<Lsn>) -> Option<(Lsn, Lsn, Vec<u8>)> {
+        let frames = self.storage_journal().frames_after(after);
+        concat_frames(&frames)
+    }
+
+    fn apply_frame(&mut self, _start_lsn: Lsn, _end_lsn: Lsn, data: Vec<u8>) -> anyhow::Result<()> {
+        // `apply_frame` isn't told which client timeline the frame came
+        // from, so the received bytes are folded into storage directly
+        // rather than through a timeline journal; `applied_through` is not
+        // advanced by frames received this way.
+        self.mutate_direct(|conn| {
+            conn.execute_batch(&String::from_utf8_lossy(&data))?;
+            Ok(())
+        })
+    }
+
+    fn durable_lsn_for(&self, stream_id: JournalId) -> Option<Lsn> {
+        if stream_id == self.doc_id() {
+            self.current_lsn()
+        } else {
+            self.applied_through(stream_id)
+        }
+    }
+}
+
+fn concat_frames(frames: &[crate::journal::JournalFrame]) -> Option<(Lsn, Lsn, Vec<u8>)> {
+    let first = frames.first()?;
+    let last = frames.last()?;
+    let data = frames.iter().flat_map(|f| f.data.iter().copied()).collect();
+    Some((first.lsn, last.lsn, data))
+}
+
+/// Tracks the state of an in-progress outbound frame for one stream, so
+/// repeated drains hand back successive chunks instead of the whole frame
+/// at once.
+struct PendingSend {
+    priority: u8,
+    frame_id: u64,
+    next_seq: u32,
+    start_lsn: Lsn,
+    end_lsn: Lsn,
+    data: Vec<u8>,
+    offset: usize,
+}
+
+/// Tracks the chunks received so far for an in-progress inbound frame.
+struct PendingRecv {
+    start_lsn: Lsn,
+    end_lsn: Lsn,
+    data: Vec<u8>,
+}
+
+/// The fields `FrameChunk` and `CompressedFrameChunk` share, bundled so
+/// `receive_chunk` can stay generic over which variant it came from.
+struct ChunkHeader {
+    stream_id: JournalId,
+    frame_id: u64,
+    start_lsn: Lsn,
+    end_lsn: Lsn,
+    is_last: bool,
+}
+
+/// Drives the replication handshake and frame transfer for one
+/// connection. A single instance can multiplex many documents (streams)
+/// over that connection: outbound frame data is queued per stream and
+/// drained highest-priority-first, round-robining within a priority so
+/// one busy document can't starve the others, while inbound messages are
+/// reassembled per `stream_id` so interleaved frames from different
+/// documents never get mixed up. Stateless across connections: create a
+/// fresh instance per socket.
+pub struct ReplicationProtocol {
+    next_frame_id: u64,
+    sends: HashMap<JournalId, PendingSend>,
+    recv: HashMap<(JournalId, u64), PendingRecv>,
+    /// priority -> streams with an armed `PendingSend` waiting to drain,
+    /// in round-robin order.
+    queues: BTreeMap<u8, VecDeque<JournalId>>,
+    queued: HashSet<JournalId>,
+    /// The highest LSN each stream's peer is already known to hold,
+    /// whether learned from an in-session ack or a resume handshake, so
+    /// outbound sends never resend frames the peer already has.
+    floors: HashMap<JournalId, Lsn>,
+    /// This side's own capabilities, recorded by `hello` so `handle` can
+    /// reconcile them against whatever the peer advertises back.
+    local_capabilities: Option<Capabilities>,
+    /// The capabilities negotiated for this session, once both sides'
+    /// `Hello`/`HelloAck` have been exchanged.
+    capabilities: Option<Capabilities>,
+    /// Per-session opt-out for compression, independent of whether both
+    /// peers support it. Defaults to on; see [`Self::set_compression_enabled`].
+    compression_enabled: bool,
+    /// Set once `shutdown` has been called: `queue_sync` stops accepting
+    /// new outbound frames, though anything already queued still drains
+    /// normally through `next_outbound`.
+    shutting_down: bool,
+    /// Set once a `Goodbye` has been received from the peer.
+    peer_done: bool,
+    /// The last `end_lsn` sent for each stream whose final chunk has gone
+    /// out, kept until an `Ack` for at least that LSN raises `floors` to
+    /// match — so `is_drained` can tell "handed to `next_outbound`" apart
+    /// from "the peer has durably confirmed it".
+    unacked_through: HashMap<JournalId, Lsn>,
+}
+
+impl ReplicationProtocol {
+    pub fn new() -> Self {
+        Self {
+            next_frame_id: 0,
+            sends: HashMap::new(),
+            recv: HashMap::new(),
+            queues: BTreeMap::new(),
+            queued: HashSet::new(),
+            floors: HashMap::new(),
+            local_capabilities: None,
+            capabilities: None,
+            compression_enabled: true,
+            shutting_down: false,
+            peer_done: false,
+            unacked_through: HashMap::new(),
+        }
+    }
+
+    /// Opts this session in or out of compressing outbound frame data,
+    /// independent of whether the peer supports it. Useful for a caller
+    /// that already knows its payloads are incompressible (e.g. already
+    /// encrypted) and would rather skip the codec's overhead entirely.
+    pub fn set_compression_enabled(&mut self, enabled: bool) {
+        self.compression_enabled = enabled;
+    }
+
+    /// Begins a graceful shutdown: `queue_sync` stops accepting new
+    /// outbound frames from this point on. This does *not* flush anything
+    /// by itself — the caller owns the socket, so it must keep draining
+    /// with `next_outbound` (and feeding inbound `Ack`s to `handle`) until
+    /// [`Self::is_drained`] is true before it sends the returned `Goodbye`;
+    /// only then is every frame queued before shutdown both sent *and*
+    /// durably acknowledged by the peer.
+    pub fn shutdown(&mut self) -> ReplicationMsg {
+        self.shutting_down = true;
+        ReplicationMsg::Goodbye
+    }
+
+    /// Whether `shutdown` has been called, every frame queued before it has
+    /// fully drained through `next_outbound`, *and* the peer has acked all
+    /// of it. The caller should only send the `Goodbye` from `shutdown`
+    /// once this is true.
+    pub fn is_drained(&self) -> bool {
+        self.shutting_down && self.sends.is_empty() && self.unacked_through.is_empty()
+    }
+
+    /// Whether the peer has sent its own `Goodbye`, meaning it won't send
+    /// anything further on this connection.
+    pub fn peer_done(&self) -> bool {
+        self.peer_done
+    }
+
+    /// Builds the message this side sends first on a new connection,
+    /// before any document's `Start`, advertising `capabilities`.
+    pub fn hello(&mut self, capabilities: Capabilities) -> ReplicationMsg {
+        self.local_capabilities = Some(capabilities.clone());
+        ReplicationMsg::Hello { capabilities }
+    }
+
+    /// The capabilities negotiated for this session, once the handshake
+    /// has completed. `None` before then, or if this protocol instance is
+    /// being used without a `Hello` exchange at all.
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    /// Whether `feature` was advertised by both sides of this session.
+    /// Before the handshake completes, nothing is assumed supported.
+    pub fn supports(&self, feature: Features) -> bool {
+        self.capabilities.as_ref().is_some_and(|c| c.features.contains(feature))
+    }
+
+    /// Builds the message this side sends first for a document, when it
+    /// starts being replicated over this connection for the first time.
+    pub fn start<D: ReplicatedDocument>(&mut self, doc: &mut D, priority: u8) -> ReplicationMsg {
+        ReplicationMsg::Start {
+            stream_id: doc.doc_id(),
+            priority,
+            storage_lsn: doc.current_lsn(),
+            resume: None,
+        }
+    }
+
+    /// Builds the handshake message for reattaching to a document after a
+    /// disconnect, carrying what this side already durably holds so the
+    /// peer can skip resending it: `last_storage_lsn` is this side's own
+    /// storage checkpoint, and `timeline_ranges` is the high-water mark it
+    /// last believes it sent for each timeline feeding this stream (most
+    /// callers have exactly one: their own).
+    pub fn resume<D: ReplicatedDocument>(
+        &mut self,
+        doc: &mut D,
+        priority: u8,
+        last_storage_lsn: Option<Lsn>,
+        timeline_ranges: Vec<(JournalId, Lsn)>,
+    ) -> ReplicationMsg {
+        ReplicationMsg::Start {
+            stream_id: doc.doc_id(),
+            priority,
+            storage_lsn: doc.current_lsn(),
+            resume: Some(ResumeCheckpoint { last_storage_lsn, timeline_ranges }),
+        }
+    }
+
+    /// Raises the floor recorded for `stream_id`, if `lsn` is higher than
+    /// what's already known, so outbound sends for it skip everything up
+    /// to and including `lsn`. Also clears `unacked_through` for the
+    /// stream once the peer has caught up to what was last sent, which is
+    /// what lets `is_drained` notice a shutdown's outstanding acks landing.
+    fn raise_floor(&mut self, stream_id: JournalId, lsn: Lsn) {
+        let floor = self.floors.entry(stream_id).or_insert(lsn);
+        *floor = (*floor).max(lsn);
+        if let std::collections::hash_map::Entry::Occupied(entry) =
+            self.unacked_through.entry(stream_id)
+        {
+            if *entry.get() <= *floor {
+                entry.remove();
+            }
+        }
+    }
+
+    /// Handles an inbound message for `doc`, which the caller must have
+    /// already looked up by `msg.stream_id()`. Reads any trailing chunk
+    /// payload from `reader`. Returns the replies to send back, in order;
+    /// usually at most one, but a resumed `Start` naming several timelines
+    /// (a `CoordinatorDocument` fed by multiple clients) can warrant one
+    /// `ResumeRange` per timeline that isn't already caught up.
+    pub fn handle<D: ReplicatedDocument, R: Read>(
+        &mut self,
+        doc: &mut D,
+        msg: ReplicationMsg,
+        reader: &mut R,
+    ) -> anyhow::Result<Vec<ReplicationMsg>> {
+        match msg {
+            ReplicationMsg::Hello { capabilities: remote } => {
+                let local = self
+                    .local_capabilities
+                    .clone()
+                    .unwrap_or_else(|| Capabilities::new(Features::NONE, remote.reducer_hash));
+                if !local.compatible_with(&remote) {
+                    return Ok(vec![ReplicationMsg::Incompatible {
+                        reason: format!(
+                            "reducer hash mismatch: local {:#x}, remote {:#x}",
+                            local.reducer_hash, remote.reducer_hash
+                        ),
+                    }]);
+                }
+                let effective = local.intersect(&remote);
+                self.capabilities = Some(effective.clone());
+                Ok(vec![ReplicationMsg::HelloAck { capabilities: effective }])
+            }
+            ReplicationMsg::HelloAck { capabilities } => {
+                self.capabilities = Some(capabilities);
+                Ok(Vec::new())
+            }
+            ReplicationMsg::Incompatible { reason } => {
+                anyhow::bail!("replication handshake rejected by peer: {reason}")
+            }
+            ReplicationMsg::Start { resume: None, .. } => Ok(Vec::new()),
+            ReplicationMsg::Start { stream_id, resume: Some(checkpoint), .. } => {
+                // the peer's storage checkpoint is what it holds of *our*
+                // outbound stream, which is keyed by our own doc id.
+                if let Some(last_storage_lsn) = checkpoint.last_storage_lsn {
+                    self.raise_floor(doc.doc_id(), last_storage_lsn);
+                }
+                // of everything the peer claims to have already sent for
+                // its timelines, reply for every one where we aren't
+                // caught up, so each knows exactly where to resume instead
+                // of guessing whether its last batch made it through.
+                let replies = checkpoint
+                    .timeline_ranges
+                    .into_iter()
+                    .filter_map(|(timeline_id, claimed_lsn)| {
+                        let durable = doc.durable_lsn_for(timeline_id);
+                        if durable >= Some(claimed_lsn) {
+                            return None;
+                        }
+                        Some(ReplicationMsg::ResumeRange { stream_id, timeline_id, needed_from: durable })
+                    })
+                    .collect();
+                Ok(replies)
+            }
+            ReplicationMsg::ResumeRange { stream_id, needed_from, .. } => {
+                if let Some(needed_from) = needed_from {
+                    self.raise_floor(stream_id, needed_from);
+                }
+                Ok(Vec::new())
+            }
+            ReplicationMsg::Ack { stream_id, through_lsn } => {
+                self.raise_floor(stream_id, through_lsn);
+                Ok(Vec::new())
+            }
+            ReplicationMsg::FrameChunk {
+                stream_id,
+                frame_id,
+                start_lsn,
+                end_lsn,
+                len,
+                is_last,
+                ..
+            } => {
+                let mut payload = vec![0u8; len as usize];
+                reader.read_exact(&mut payload)?;
+                let header = ChunkHeader { stream_id, frame_id, start_lsn, end_lsn, is_last };
+                Ok(self.receive_chunk(doc, header, payload)?.into_iter().collect())
+            }
+            ReplicationMsg::CompressedFrameChunk {
+                stream_id,
+                frame_id,
+                start_lsn,
+                end_lsn,
+                algo,
+                uncompressed_len,
+                len,
+                is_last,
+                ..
+            } => {
+                let mut payload = vec![0u8; len as usize];
+                reader.read_exact(&mut payload)?;
+                let payload = algo.decompress(&payload, uncompressed_len as usize);
+                let header = ChunkHeader { stream_id, frame_id, start_lsn, end_lsn, is_last };
+                Ok(self.receive_chunk(doc, header, payload)?.into_iter().collect())
+            }
+            ReplicationMsg::Goodbye => {
+                self.peer_done = true;
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Accumulates one chunk's (already decompressed) payload for
+    /// `(header.stream_id, header.frame_id)`, applying the reassembled frame
+    /// once `header.is_last` closes it out. Shared by `FrameChunk` and
+    /// `CompressedFrameChunk`, which differ only in how their payload got
+    /// onto the wire.
+    fn receive_chunk<D: ReplicatedDocument>(
+        &mut self,
+        doc: &mut D,
+        header: ChunkHeader,
+        payload: Vec<u8>,
+    ) -> anyhow::Result<Option<ReplicationMsg>> {
+        let ChunkHeader { stream_id, frame_id, start_lsn, end_lsn, is_last } = header;
+        let pending = self
+            .recv
+            .entry((stream_id, frame_id))
+            .or_insert_with(|| PendingRecv { start_lsn, end_lsn, data: Vec::new() });
+        pending.data.extend_from_slice(&payload);
+
+        if !is_last {
+            return Ok(None);
+        }
+
+        // a full-size final chunk is still a final chunk: `is_last` is what
+        // ends the frame, not an extra empty chunk after it.
+        let pending = self.recv.remove(&(stream_id, frame_id)).expect("just inserted");
+        doc.apply_frame(pending.start_lsn, pending.end_lsn, pending.data)?;
+        Ok(Some(ReplicationMsg::Ack { stream_id, through_lsn: end_lsn }))
+    }
+
+    /// Arms `doc` for outbound replication at `priority`, if it isn't
+    /// already queued and has frame data pending. Call this once per
+    /// document per loop tick, for every document this connection
+    /// replicates, before draining with [`Self::next_outbound`].
+    pub fn queue_sync<D: ReplicatedDocument>(
+        &mut self,
+        doc: &mut D,
+        priority: u8,
+    ) -> anyhow::Result<()> {
+        if self.shutting_down {
+            return Ok(());
+        }
+        let stream_id = doc.doc_id();
+        if self.queued.contains(&stream_id) {
+            return Ok(());
+        }
+        let floor = self.floors.get(&stream_id).copied();
+        let Some((start_lsn, end_lsn, data)) = doc.take_pending_frame(floor) else {
+            return Ok(());
+        };
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let frame_id = self.next_frame_id;
+        self.next_frame_id += 1;
+        self.sends.insert(
+            stream_id,
+            PendingSend { priority, frame_id, next_seq: 0, start_lsn, end_lsn, data, offset: 0 },
+        );
+        self.queues.entry(priority).or_default().push_back(stream_id);
+        self.queued.insert(stream_id);
+        Ok(())
+    }
+
+    /// Drains the next outbound chunk across every queued stream: the
+    /// lowest-numbered (highest) non-empty priority always goes first,
+    /// and streams within a priority take turns so a large document can't
+    /// starve a small one. Returns the chunk's header message and a
+    /// reader over its payload; the caller is expected to send the
+    /// message, then copy the reader's bytes immediately after it.
+    pub fn next_outbound(&mut self) -> Option<(ReplicationMsg, FrameReader)> {
+        // a peer that didn't advertise chunking support gets the whole
+        // frame as a single "chunk" instead, since it has no way to
+        // reassemble a split one.
+        let chunk_size = if self.supports(Features::FRAME_CHUNKING) { CHUNK_SIZE } else { usize::MAX };
+        let compression_supported = self.compression_enabled && self.supports(Features::COMPRESSION);
+
+        let priority = *self.queues.iter().find(|(_, q)| !q.is_empty())?.0;
+        let queue = self.queues.get_mut(&priority).expect("checked above");
+        let stream_id = queue.pop_front().expect("checked above");
+
+        let send = self.sends.get_mut(&stream_id).expect("queued stream has a pending send");
+        let remaining = send.data.len() - send.offset;
+        let take = remaining.min(chunk_size);
+        let chunk = send.data[send.offset..send.offset + take].to_vec();
+        send.offset += take;
+        let is_last = send.offset == send.data.len();
+        let seq = send.next_seq;
+        send.next_seq += 1;
+
+        let worth_trying = compression_supported && chunk.len() >= COMPRESSION_MIN_SIZE;
+        let algo = CompressionAlgo::Rle;
+        let compressed = worth_trying.then(|| algo.compress(&chunk));
+        // RLE only helps on data with long runs of identical bytes; on
+        // anything else it can expand the payload (2 bytes out per 1 byte
+        // in, worst case), so fall back to sending `chunk` uncompressed
+        // whenever the "compressed" form isn't actually smaller.
+        let should_compress = compressed.as_ref().is_some_and(|c| c.len() < chunk.len());
+
+        let (msg, payload) = if should_compress {
+            let compressed = compressed.expect("should_compress implies Some");
+            let msg = ReplicationMsg::CompressedFrameChunk {
+                stream_id,
+                priority: send.priority,
+                frame_id: send.frame_id,
+                seq,
+                start_lsn: send.start_lsn,
+                end_lsn: send.end_lsn,
+                algo,
+                uncompressed_len: chunk.len() as u32,
+                len: compressed.len() as u32,
+                is_last,
+            };
+            (msg, compressed)
+        } else {
+            let msg = ReplicationMsg::FrameChunk {
+                stream_id,
+                priority: send.priority,
+                frame_id: send.frame_id,
+                seq,
+                start_lsn: send.start_lsn,
+                end_lsn: send.end_lsn,
+                len: chunk.len() as u32,
+                is_last,
+            };
+            (msg, chunk)
+        };
+
+        if is_last {
+            let end_lsn = self.sends.remove(&stream_id).expect("just looked up").end_lsn;
+            self.queued.remove(&stream_id);
+            // outstanding until an `Ack` (or a resume's `ResumeRange`) for
+            // at least this LSN raises the stream's floor to match.
+            self.unacked_through.insert(stream_id, end_lsn);
+        } else {
+            // round-robin: this stream still has data, so it goes to the
+            // back of its priority's queue instead of being drained dry.
+            queue.push_back(stream_id);
+        }
+
+        Some((msg, FrameReader(Cursor::new(payload))))
+    }
+}
+
+impl Default for ReplicationProtocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal `ReplicatedDocument` for driving `ReplicationProtocol`
+    /// directly, without the SQLite/WASM machinery `LocalDocument` and
+    /// `CoordinatorDocument` carry.
+    struct TestDoc {
+        id: JournalId,
+        lsn: Option<Lsn>,
+        pending: Option<(Lsn, Lsn, Vec<u8>)>,
+        applied: Vec<(Lsn, Lsn, Vec<u8>)>,
+        durable: HashMap<JournalId, Lsn>,
+    }
+
+    impl TestDoc {
+        fn new(id: JournalId) -> Self {
+            Self { id, lsn: None, pending: None, applied: Vec::new(), durable: HashMap::new() }
+        }
+
+        fn queue(&mut self, start_lsn: Lsn, end_lsn: Lsn, data: Vec<u8>) {
+            self.lsn = Some(end_lsn);
+            self.pending = Some((start_lsn, end_lsn, data));
+        }
+    }
+
+    impl ReplicatedDocument for TestDoc {
+        fn doc_id(&self) -> JournalId {
+            self.id
+        }
+
+        fn current_lsn(&self) -> Option<Lsn> {
+            self.lsn
+        }
+
+        fn take_pending_frame(&mut self, after: Option<Lsn>) -> Option<(Lsn, Lsn, Vec<u8>)> {
+            let (_, end_lsn, _) = self.pending.as_ref()?;
+            if after.is_some_and(|floor| floor >= *end_lsn) {
+                return None;
+            }
+            self.pending.take()
+        }
+
+        fn apply_frame(&mut self, start_lsn: Lsn, end_lsn: Lsn, data: Vec<u8>) -> anyhow::Result<()> {
+            self.applied.push((start_lsn, end_lsn, data));
+            Ok(())
+        }
+
+        fn durable_lsn_for(&self, stream_id: JournalId) -> Option<Lsn> {
+            self.durable.get(&stream_id).copied()
+        }
+    }
+
+    /// Exchanges `Hello`/`HelloAck` both ways so `a` and `b` negotiate
+    /// `features` between themselves, the same as two real peers would
+    /// before exchanging any frame data.
+    fn negotiate(a: &mut ReplicationProtocol, b: &mut ReplicationProtocol, features: Features) {
+        let mut scratch = TestDoc::new(JournalId::new256(&mut rand::thread_rng()));
+        let hello_a = a.hello(Capabilities::new(features, 0));
+        let hello_b = b.hello(Capabilities::new(features, 0));
+        for reply in a.handle(&mut scratch, hello_b, &mut io::empty()).unwrap() {
+            assert!(b.handle(&mut scratch, reply, &mut io::empty()).unwrap().is_empty());
+        }
+        for reply in b.handle(&mut scratch, hello_a, &mut io::empty()).unwrap() {
+            assert!(a.handle(&mut scratch, reply, &mut io::empty()).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn mismatched_reducer_hashes_are_rejected_as_incompatible() {
+        let mut scratch = TestDoc::new(JournalId::new256(&mut rand::thread_rng()));
+        let mut a = ReplicationProtocol::new();
+        let mut b = ReplicationProtocol::new();
+
+        a.hello(Capabilities::new(Features::NONE, 1));
+        let hello_b = b.hello(Capabilities::new(Features::NONE, 2));
+
+        let replies = a.handle(&mut scratch, hello_b, &mut io::empty()).unwrap();
+        assert!(matches!(replies.as_slice(), [ReplicationMsg::Incompatible { .. }]));
+
+        // the peer that receives `Incompatible` bails outright rather than
+        // trying to carry on with a reducer it can't agree with `a` on.
+        assert!(b.handle(&mut scratch, replies.into_iter().next().unwrap(), &mut io::empty()).is_err());
+
+        // `a`'s own handshake never completes, so nothing gets negotiated.
+        assert!(a.capabilities().is_none());
+    }
+
+    #[test]
+    fn matching_reducer_hashes_negotiate_the_intersected_features() {
+        let mut scratch = TestDoc::new(JournalId::new256(&mut rand::thread_rng()));
+        let mut a = ReplicationProtocol::new();
+        let mut b = ReplicationProtocol::new();
+
+        let hello_a = a.hello(Capabilities::new(Features::FRAME_CHUNKING | Features::RESUME, 42));
+        let hello_b = b.hello(Capabilities::new(Features::FRAME_CHUNKING | Features::COMPRESSION, 42));
+
+        let replies = a.handle(&mut scratch, hello_b, &mut io::empty()).unwrap();
+        let ack = match replies.as_slice() {
+            [ReplicationMsg::HelloAck { capabilities }] => capabilities.clone(),
+            other => panic!("unexpected reply: {other:?}"),
+        };
+
+        // only the feature both sides advertised survives the intersection.
+        assert!(ack.features.contains(Features::FRAME_CHUNKING));
+        assert!(!ack.features.contains(Features::RESUME));
+        assert!(!ack.features.contains(Features::COMPRESSION));
+        assert_eq!(ack.reducer_hash, 42);
+        assert_eq!(a.capabilities(), Some(&ack));
+
+        assert!(matches!(
+            b.handle(&mut scratch, hello_a, &mut io::empty()).unwrap().as_slice(),
+            [ReplicationMsg::HelloAck { .. }]
+        ));
+    }
+
+    #[test]
+    fn reassembles_a_frame_split_across_multiple_chunks() {
+        let stream_id = JournalId::new256(&mut rand::thread_rng());
+
+        let mut sender = ReplicationProtocol::new();
+        let mut receiver = ReplicationProtocol::new();
+        negotiate(&mut sender, &mut receiver, Features::FRAME_CHUNKING);
+
+        let data = vec![0xABu8; CHUNK_SIZE * 2 + 10];
+        let mut source = TestDoc::new(stream_id);
+        source.queue(1, 1, data.clone());
+        sender.queue_sync(&mut source, PRIORITY_NORMAL).unwrap();
+
+        let mut sink = TestDoc::new(stream_id);
+        let mut num_chunks = 0;
+        let mut acks = Vec::new();
+        while let Some((msg, mut reader)) = sender.next_outbound() {
+            num_chunks += 1;
+            let is_last = matches!(msg, ReplicationMsg::FrameChunk { is_last, .. } if is_last);
+            for reply in receiver.handle(&mut sink, msg, &mut reader).unwrap() {
+                assert!(matches!(reply, ReplicationMsg::Ack { .. }));
+                acks.push(reply);
+            }
+            // the frame isn't closed out until the chunk that ends it.
+            assert_eq!(sink.applied.is_empty(), !is_last);
+        }
+
+        // a frame this size, chunked at `CHUNK_SIZE`, must take more than
+        // one chunk to cross the wire.
+        assert_eq!(num_chunks, 3);
+        assert_eq!(acks.len(), 1);
+        assert_eq!(sink.applied.len(), 1);
+        assert_eq!(sink.applied[0], (1, 1, data));
+    }
+
+    #[test]
+    fn resume_builds_a_start_message_carrying_the_checkpoint() {
+        let mut rng = rand::thread_rng();
+        let stream_id = JournalId::new256(&mut rng);
+        let timeline_id = JournalId::new128(&mut rng);
+
+        let mut protocol = ReplicationProtocol::new();
+        let mut doc = TestDoc::new(stream_id);
+        doc.lsn = Some(7);
+
+        let msg = protocol.resume(&mut doc, PRIORITY_NORMAL, Some(3), vec![(timeline_id, 5)]);
+
+        match msg {
+            ReplicationMsg::Start { stream_id: id, priority, storage_lsn, resume } => {
+                assert_eq!(id, stream_id);
+                assert_eq!(priority, PRIORITY_NORMAL);
+                assert_eq!(storage_lsn, Some(7));
+                let checkpoint = resume.expect("resume() must carry a checkpoint");
+                assert_eq!(checkpoint.last_storage_lsn, Some(3));
+                assert_eq!(checkpoint.timeline_ranges, vec![(timeline_id, 5)]);
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn resumed_start_answers_every_named_timeline() {
+        let mut rng = rand::thread_rng();
+        let stream_id = JournalId::new256(&mut rng);
+        let caught_up = JournalId::new128(&mut rng);
+        let behind = JournalId::new128(&mut rng);
+        let never_seen = JournalId::new128(&mut rng);
+
+        let mut protocol = ReplicationProtocol::new();
+        let mut doc = TestDoc::new(stream_id);
+        doc.durable.insert(caught_up, 10);
+        doc.durable.insert(behind, 4);
+        // `never_seen` has no entry, so `durable_lsn_for` reports `None`.
+
+        let msg = ReplicationMsg::Start {
+            stream_id,
+            priority: PRIORITY_NORMAL,
+            storage_lsn: None,
+            resume: Some(ResumeCheckpoint {
+                last_storage_lsn: None,
+                timeline_ranges: vec![(caught_up, 10), (behind, 8), (never_seen, 5)],
+            }),
+        };
+
+        let replies = protocol.handle(&mut doc, msg, &mut io::empty()).unwrap();
+        let needed: HashMap<JournalId, Option<Lsn>> = replies
+            .into_iter()
+            .map(|reply| match reply {
+                ReplicationMsg::ResumeRange { timeline_id, needed_from, .. } => {
+                    (timeline_id, needed_from)
+                }
+                other => panic!("unexpected reply: {other:?}"),
+            })
+            .collect();
+
+        // every timeline the peer named gets answered except the one
+        // that's already fully caught up - not just the first entry.
+        assert_eq!(needed.len(), 2);
+        assert_eq!(needed.get(&behind), Some(&Some(4)));
+        assert_eq!(needed.get(&never_seen), Some(&None));
+        assert!(!needed.contains_key(&caught_up));
+    }
+
+    #[test]
+    fn next_outbound_prefers_higher_priority_streams() {
+        let mut protocol = ReplicationProtocol::new();
+        let mut peer = ReplicationProtocol::new();
+        negotiate(&mut protocol, &mut peer, Features::NONE);
+
+        let normal_id = JournalId::new256(&mut rand::thread_rng());
+        let control_id = JournalId::new256(&mut rand::thread_rng());
+        let mut normal_doc = TestDoc::new(normal_id);
+        let mut control_doc = TestDoc::new(control_id);
+        normal_doc.queue(1, 1, vec![1, 2, 3]);
+        control_doc.queue(1, 1, vec![4, 5, 6]);
+
+        // queued normal-priority-first, so it's the priority check - not
+        // insertion order - that must put `control` ahead of it.
+        protocol.queue_sync(&mut normal_doc, PRIORITY_NORMAL).unwrap();
+        protocol.queue_sync(&mut control_doc, PRIORITY_CONTROL).unwrap();
+
+        let (msg, _) = protocol.next_outbound().unwrap();
+        assert_eq!(msg.stream_id(), Some(control_id));
+
+        let (msg, _) = protocol.next_outbound().unwrap();
+        assert_eq!(msg.stream_id(), Some(normal_id));
+
+        assert!(protocol.next_outbound().is_none());
+    }
+
+    #[test]
+    fn next_outbound_round_robins_within_a_priority() {
+        let mut protocol = ReplicationProtocol::new();
+        let mut peer = ReplicationProtocol::new();
+        negotiate(&mut protocol, &mut peer, Features::FRAME_CHUNKING);
+
+        let a_id = JournalId::new256(&mut rand::thread_rng());
+        let b_id = JournalId::new256(&mut rand::thread_rng());
+        let mut a_doc = TestDoc::new(a_id);
+        let mut b_doc = TestDoc::new(b_id);
+        a_doc.queue(1, 1, vec![0xAAu8; CHUNK_SIZE + 10]);
+        b_doc.queue(1, 1, vec![0xBBu8; CHUNK_SIZE + 10]);
+
+        protocol.queue_sync(&mut a_doc, PRIORITY_NORMAL).unwrap();
+        protocol.queue_sync(&mut b_doc, PRIORITY_NORMAL).unwrap();
+
+        // each stream only gets one chunk's turn before the other, instead
+        // of `a` being drained dry before `b` sends anything at all.
+        let order: Vec<JournalId> =
+            (0..4).map(|_| protocol.next_outbound().unwrap().0.stream_id().unwrap()).collect();
+        assert_eq!(order, vec![a_id, b_id, a_id, b_id]);
+    }
+
+    #[test]
+    fn rle_round_trips_runs_of_repeated_bytes() {
+        let data = [vec![0u8; 300], vec![7u8; 5], vec![9u8; 1]].concat();
+        let compressed = rle_compress(&data);
+        let decompressed = rle_decompress(&compressed, data.len());
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn next_outbound_compresses_highly_repetitive_chunks() {
+        let stream_id = JournalId::new256(&mut rand::thread_rng());
+        let mut a = ReplicationProtocol::new();
+        let mut b = ReplicationProtocol::new();
+        negotiate(&mut a, &mut b, Features::COMPRESSION);
+
+        let data = vec![0u8; COMPRESSION_MIN_SIZE * 2];
+        let mut doc = TestDoc::new(stream_id);
+        doc.queue(1, 1, data);
+        a.queue_sync(&mut doc, PRIORITY_NORMAL).unwrap();
+
+        let (msg, _reader) = a.next_outbound().expect("frame was queued");
+        assert!(
+            matches!(msg, ReplicationMsg::CompressedFrameChunk { .. }),
+            "expected a compressed chunk for highly repetitive data, got {msg:?}"
+        );
+    }
+
+    #[test]
+    fn next_outbound_falls_back_to_uncompressed_when_rle_would_expand() {
+        let stream_id = JournalId::new256(&mut rand::thread_rng());
+        let mut a = ReplicationProtocol::new();
+        let mut b = ReplicationProtocol::new();
+        negotiate(&mut a, &mut b, Features::COMPRESSION);
+
+        // no two adjacent bytes repeat, so RLE would emit 2 output bytes
+        // per 1 input byte - exactly the case `CompressedFrameChunk`
+        // should never be worth sending.
+        let data: Vec<u8> =
+            (0..COMPRESSION_MIN_SIZE as u32 * 2).map(|i| (i % 2) as u8 * 255).collect();
+        let mut doc = TestDoc::new(stream_id);
+        doc.queue(1, 1, data);
+        a.queue_sync(&mut doc, PRIORITY_NORMAL).unwrap();
+
+        let (msg, _reader) = a.next_outbound().expect("frame was queued");
+        assert!(
+            matches!(msg, ReplicationMsg::FrameChunk { .. }),
+            "expected an uncompressed fallback, got {msg:?}"
+        );
+    }
+}